@@ -1,16 +1,120 @@
+use std::io::{Error as IoError, ErrorKind};
 use std::net::SocketAddr;
 use std::time::Duration;
 
 use log::{info, error};
-use env_logger::Builder as EnvBuilder;
 use hashbrown::HashMap;
-use tokio::codec::{FramedRead, LinesCodec, LinesCodecError};
+use serde::Deserialize;
 use tokio::net::{TcpListener, TcpStream};
 use tokio::prelude::*;
-use tokio::runtime::{Builder, TaskExecutor};
+use tokio::runtime::{Builder, Handle};
+use tokio::stream::StreamExt;
+use tokio::sync::{RwLock, Semaphore};
+use tokio::time::timeout;
+use tokio_util::codec::{FramedRead, LinesCodec, LinesCodecError};
 
 use lazy_static::lazy_static;
 
+mod rdap;
+
+const IANA_WHOIS_HOST: &str = "whois.iana.org";
+
+// Upper bound on registrar referrals to follow before giving up.
+const MAX_REFERRAL_HOPS: usize = 3;
+
+// Path to the server config file, as an alternative to the first CLI arg.
+const CONFIG_PATH_ENV: &str = "RWHOIS_CONFIG";
+
+const CONNECT_TIMEOUT_ENV: &str = "RWHOIS_CONNECT_TIMEOUT_MS";
+const READ_TIMEOUT_ENV: &str = "RWHOIS_READ_TIMEOUT_MS";
+const MAX_CONCURRENT_UPSTREAM_ENV: &str = "RWHOIS_MAX_CONCURRENT_UPSTREAM";
+
+const DEFAULT_CONNECT_TIMEOUT_MS: u64 = 5_000;
+const DEFAULT_READ_TIMEOUT_MS: u64 = 10_000;
+const DEFAULT_MAX_CONCURRENT_UPSTREAM: usize = 64;
+
+fn duration_from_env(key: &str, default_ms: u64) -> Duration {
+    let ms = std::env::var(key)
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(default_ms);
+    Duration::from_millis(ms)
+}
+
+fn count_from_env(key: &str, default: usize) -> usize {
+    std::env::var(key)
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(default)
+}
+
+fn upstream_timeout_error(op: &str) -> IoError {
+    IoError::new(ErrorKind::TimedOut, format!("upstream {} timed out", op))
+}
+
+fn default_port() -> u16 {
+    43
+}
+
+fn default_query_template() -> String {
+    "$addr\r\n".to_string()
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct ServerEntry {
+    host: String,
+    #[serde(default = "default_port")]
+    port: u16,
+    #[serde(default = "default_query_template")]
+    query_template: String,
+}
+
+impl ServerEntry {
+    fn from_lut_host(host: &str) -> ServerEntry {
+        ServerEntry {
+            host: host.to_string(),
+            port: default_port(),
+            query_template: default_query_template(),
+        }
+    }
+
+    fn render_query(&self, addr: &str) -> String {
+        self.query_template.replace("$addr", addr)
+    }
+}
+
+fn load_server_config() -> HashMap<String, ServerEntry> {
+    let path = std::env::var(CONFIG_PATH_ENV)
+        .ok()
+        .or_else(|| std::env::args().nth(1));
+    load_server_config_from(path.as_deref())
+}
+
+// Missing, unset, or unparseable config is treated as "no overrides"
+// rather than a fatal error, since `WHOIS_LUT` remains usable on its own.
+fn load_server_config_from(path: Option<&str>) -> HashMap<String, ServerEntry> {
+    let path = match path {
+        Some(p) => p,
+        None => return HashMap::new(),
+    };
+
+    let contents = match std::fs::read_to_string(path) {
+        Ok(c) => c,
+        Err(e) => {
+            error!("unable to read server config at {}: {:?}", path, e);
+            return HashMap::new();
+        }
+    };
+
+    match serde_json::from_str::<HashMap<String, ServerEntry>>(&contents) {
+        Ok(entries) => entries,
+        Err(e) => {
+            error!("unable to parse server config at {}: {:?}", path, e);
+            HashMap::new()
+        }
+    }
+}
+
 lazy_static! {
     static ref WHOIS_LUT: HashMap<&'static str, &'static str> = {
         let mut m = HashMap::new();
@@ -29,7 +133,199 @@ lazy_static! {
         m.insert("org", "whois.publicregistry.net");
         m
     };
+
+    // Discovered at runtime via whois.iana.org; seeded lazily and reused
+    // for every subsequent query against the same TLD.
+    static ref DISCOVERED_LUT: RwLock<HashMap<String, String>> = RwLock::new(HashMap::new());
+
+    // User-supplied overrides loaded from the server config file, keyed by
+    // TLD. Takes precedence over both `WHOIS_LUT` and `DISCOVERED_LUT`.
+    static ref SERVER_CONFIG: HashMap<String, ServerEntry> = load_server_config();
+
+    static ref CONNECT_TIMEOUT: Duration =
+        duration_from_env(CONNECT_TIMEOUT_ENV, DEFAULT_CONNECT_TIMEOUT_MS);
+    static ref READ_TIMEOUT: Duration =
+        duration_from_env(READ_TIMEOUT_ENV, DEFAULT_READ_TIMEOUT_MS);
+
+    // Caps the number of upstream whois/IANA dials in flight at once so a
+    // flood of clients can't exhaust the process's sockets.
+    static ref UPSTREAM_LIMIT: Semaphore =
+        Semaphore::new(count_from_env(MAX_CONCURRENT_UPSTREAM_ENV, DEFAULT_MAX_CONCURRENT_UPSTREAM));
+}
+
+// Returns `None` if IANA has no `whois:` referral for `tld`.
+async fn discover_whois_host(tld: &str) -> Option<String> {
+    let _permit = UPSTREAM_LIMIT.acquire().await;
+
+    let whois_addr = format!("{}:43", IANA_WHOIS_HOST);
+    let mut iana_stream = match timeout(*CONNECT_TIMEOUT, TcpStream::connect(&whois_addr)).await {
+        Ok(Ok(s)) => s,
+        Ok(Err(e)) => {
+            error!("unable to connect to {}: {:?}", IANA_WHOIS_HOST, e);
+            return None;
+        }
+        Err(_) => {
+            error!("connecting to {} timed out", IANA_WHOIS_HOST);
+            return None;
+        }
+    };
+
+    let request = format!("{}\r\n", tld);
+    let (iana_read, mut iana_write) = iana_stream.split();
+    match timeout(*READ_TIMEOUT, iana_write.write_all(request.as_bytes())).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => {
+            error!("unable to query {}: {:?}", IANA_WHOIS_HOST, e);
+            return None;
+        }
+        Err(_) => {
+            error!("querying {} timed out", IANA_WHOIS_HOST);
+            return None;
+        }
+    }
+
+    let mut lines = FramedRead::new(iana_read, LinesCodec::new());
+    loop {
+        let line = match timeout(*READ_TIMEOUT, lines.next()).await {
+            Ok(Some(l)) => l,
+            Ok(None) => break,
+            Err(_) => {
+                error!("reading from {} timed out", IANA_WHOIS_HOST);
+                break;
+            }
+        };
+        let line = match line {
+            Ok(l) => l,
+            Err(_) => continue,
+        };
+        if let Some(idx) = line.find(':') {
+            let (key, value) = line.split_at(idx);
+            if key.trim().eq_ignore_ascii_case("whois") {
+                return Some(value[1..].trim().to_string());
+            }
+        }
+    }
+
+    None
 }
+
+// Config file overrides take precedence, then the static LUT, then the
+// runtime cache.
+fn resolve_server_from(
+    tld: &str,
+    config: &HashMap<String, ServerEntry>,
+    lut: &HashMap<&str, &str>,
+    discovered: &HashMap<String, String>,
+) -> Option<ServerEntry> {
+    if let Some(entry) = config.get(tld) {
+        return Some(entry.clone());
+    }
+
+    if let Some(host) = lut.get(tld) {
+        return Some(ServerEntry::from_lut_host(host));
+    }
+
+    if let Some(host) = discovered.get(tld) {
+        return Some(ServerEntry::from_lut_host(host));
+    }
+
+    None
+}
+
+// Falls back to an IANA referral lookup once the config/LUT/cache have
+// all missed.
+async fn resolve_server(tld: &str) -> Option<ServerEntry> {
+    if let Some(server) =
+        resolve_server_from(tld, &SERVER_CONFIG, &WHOIS_LUT, &*DISCOVERED_LUT.read().await)
+    {
+        return Some(server);
+    }
+
+    let host = discover_whois_host(tld).await?;
+    DISCOVERED_LUT.write().await.insert(tld.to_string(), host.clone());
+    Some(ServerEntry::from_lut_host(&host))
+}
+
+async fn query_whois(server: &ServerEntry, query: &str) -> std::io::Result<Vec<u8>> {
+    let _permit = UPSTREAM_LIMIT.acquire().await;
+
+    let whois_addr = format!("{}:{}", server.host, server.port);
+    let mut whois_stream = timeout(*CONNECT_TIMEOUT, TcpStream::connect(&whois_addr))
+        .await
+        .map_err(|_| upstream_timeout_error("connect"))??;
+
+    let request = server.render_query(query);
+    let (mut whois_read, mut whois_write) = whois_stream.split();
+    timeout(*READ_TIMEOUT, whois_write.write_all(request.as_bytes()))
+        .await
+        .map_err(|_| upstream_timeout_error("write"))??;
+
+    let mut response = Vec::new();
+    timeout(*READ_TIMEOUT, whois_read.read_to_end(&mut response))
+        .await
+        .map_err(|_| upstream_timeout_error("read"))??;
+    Ok(response)
+}
+
+// Bare hostname of the next referral to follow, if any, stripping any
+// `whois://`/`rwhois://` scheme prefix and trailing port.
+fn find_referral(response: &str) -> Option<String> {
+    for line in response.lines() {
+        let idx = match line.find(':') {
+            Some(i) => i,
+            None => continue,
+        };
+        let (key, value) = line.split_at(idx);
+        let key = key.trim();
+        let is_referral = key.eq_ignore_ascii_case("whois")
+            || key.eq_ignore_ascii_case("ReferralServer")
+            || key.eq_ignore_ascii_case("Registrar WHOIS Server");
+        if !is_referral {
+            continue;
+        }
+
+        let mut value = value[1..].trim();
+        for scheme in &["whois://", "rwhois://"] {
+            if let Some(stripped) = value.strip_prefix(scheme) {
+                value = stripped;
+            }
+        }
+        let host = value.split('/').next().unwrap_or(value);
+        let host = host.split(':').next().unwrap_or(host);
+        if !host.is_empty() {
+            return Some(host.to_string());
+        }
+    }
+    None
+}
+
+// Only the first hop uses `server`'s configured port/template; referred
+// servers are assumed to speak plain port-43 whois.
+async fn query_whois_with_referrals(server: &ServerEntry, query: &str) -> std::io::Result<Vec<u8>> {
+    let mut current = server.clone();
+    let mut combined = Vec::new();
+
+    for hop in 0..=MAX_REFERRAL_HOPS {
+        let response = query_whois(&current, query).await?;
+        combined.extend_from_slice(format!("--- {} ---\r\n", current.host).as_bytes());
+        combined.extend_from_slice(&response);
+
+        if hop == MAX_REFERRAL_HOPS {
+            break;
+        }
+
+        let response_str = String::from_utf8_lossy(&response);
+        match find_referral(&response_str) {
+            Some(referral_host) if referral_host != current.host => {
+                current = ServerEntry::from_lut_host(&referral_host);
+            }
+            _ => break,
+        }
+    }
+
+    Ok(combined)
+}
+
 #[derive(Debug)]
 pub enum RWhoisError {
     CodecError(LinesCodecError),
@@ -46,34 +342,60 @@ async fn rwhois_process(mut stream: TcpStream, addr: SocketAddr) -> Result<(), R
                 Ok(ref l) => {
                     //println!("{}: {}", addr, l);
                     let splits: Vec<&str> = l.split_whitespace().collect();
-                    if splits.len() == 1 {
-                        //let hostname = splits[0].clone();
-                        let pairs: Vec<&str> = splits[0].split(".").collect();
+                    let (domain, use_rdap) = if splits.len() == 2
+                        && splits[0].eq_ignore_ascii_case(rdap::RDAP_QUERY_PREFIX)
+                    {
+                        (Some(splits[1]), true)
+                    } else if splits.len() == 1 {
+                        (Some(splits[0]), rdap::rdap_mode_enabled())
+                    } else {
+                        (None, false)
+                    };
+
+                    if let Some(domain) = domain {
+                        let pairs: Vec<&str> = domain.split(".").collect();
                         if pairs.len() > 1 {
+                            if use_rdap {
+                                match rdap::query_rdap(domain).await {
+                                    Ok(response) => {
+                                        if let Err(e) = client_write.write_all(&response).await {
+                                            error!(
+                                                "unable to write back response to {}: {:?}",
+                                                addr, e
+                                            );
+                                        }
+                                    }
+                                    Err(e) => {
+                                        error!(
+                                            "unable to fetch RDAP record for {}: {:?}",
+                                            domain, e
+                                        );
+                                    }
+                                }
+                                continue;
+                            }
+
                             let total_pairs = pairs.len();
-                            if let Some(whois_hostname) = WHOIS_LUT.get(pairs[total_pairs - 1]) {
+                            if let Some(server) = resolve_server(pairs[total_pairs - 1]).await {
                                 info!(
                                     "Would use whois server: {} for {}",
-                                    whois_hostname, splits[0]
+                                    server.host, domain
                                 );
-                                let whois_addr = format!("{}:43", whois_hostname);
-                                if let Ok(mut whois_stream) = TcpStream::connect(&whois_addr).await
-                                {
-                                    let request = format!("{}\r\n", splits[0]);
-                                    let (mut whois_read, mut whois_write) = whois_stream.split();
-                                    if let Ok(_) = whois_write.write_all(request.as_bytes()).await {
-                                        if let Err(e) = whois_read.copy(&mut client_write).await {
+                                match query_whois_with_referrals(&server, domain).await {
+                                    Ok(response) => {
+                                        if let Err(e) = client_write.write_all(&response).await {
                                             error!(
                                                 "unable to write back response to {}: {:?}",
                                                 addr, e
                                             );
                                         }
                                     }
-                                } else {
-                                    error!(
-                                        "unable to connect to whois server: {}",
-                                        &whois_hostname
-                                    );
+                                    Err(e) => {
+                                        error!(
+                                            "unable to query whois server {}: {:?}",
+                                            &server.host, e
+                                        );
+                                    }
                                 }
                             } else {
                                 let _ = client_write.write_all("Invalid domain name".as_bytes()).await;
@@ -97,14 +419,14 @@ async fn rwhois_process(mut stream: TcpStream, addr: SocketAddr) -> Result<(), R
     }
 }
 
-async fn rwhois_serve(exec: TaskExecutor, addr: String) -> Result<(), Box<dyn std::error::Error>> {
+async fn rwhois_serve(handle: Handle, addr: String) -> Result<(), Box<dyn std::error::Error>> {
     let mut listener = TcpListener::bind(&addr).await?;
     info!("rwhois server running on {}", &addr);
 
     loop {
         let (stream, addr) = listener.accept().await?;
 
-        exec.spawn(async move {
+        handle.spawn(async move {
             if let Err(e) = rwhois_process(stream, addr).await {
                 error!("rwhois_process error; error = {:?}", e);
             }
@@ -118,16 +440,18 @@ fn main() {
     //env_log_builder.init();
     env_logger::init();
 
-    let rt = Builder::new()
+    let mut rt = Builder::new()
+        .threaded_scheduler()
+        .enable_all()
         .core_threads(num_cpus::get_physical())
-        .blocking_threads(num_cpus::get_physical())
-        .keep_alive(Some(Duration::from_secs(10)))
-        .name_prefix("rwhois-")
+        .max_threads(num_cpus::get_physical() * 2)
+        .thread_name("rwhois-")
         .build()
         .unwrap();
 
     let addr = "127.0.0.1:9000".to_string();
-    match rt.block_on(rwhois_serve(rt.executor(), addr)) {
+    let handle = rt.handle().clone();
+    match rt.block_on(rwhois_serve(handle, addr)) {
         Ok(_) => {}
         Err(e) => {
             //eprintln!("rwhois_serve; error = {:?}", e);
@@ -135,3 +459,118 @@ fn main() {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        find_referral, load_server_config_from, resolve_server_from, HashMap, ServerEntry,
+    };
+
+    #[test]
+    fn find_referral_skips_colonless_lines_before_the_referral() {
+        let response = "\nNOTICE: some disclaimer banner\n\nRegistrar WHOIS Server: whois.example.com\n";
+        assert_eq!(find_referral(response), Some("whois.example.com".to_string()));
+    }
+
+    #[test]
+    fn find_referral_none_when_absent() {
+        let response = "Domain Name: example.com\nStatus: active\n";
+        assert_eq!(find_referral(response), None);
+    }
+
+    #[test]
+    fn render_query_substitutes_addr() {
+        let server = ServerEntry {
+            host: "whois.ripe.net".to_string(),
+            port: 43,
+            query_template: "-B $addr\r\n".to_string(),
+        };
+        assert_eq!(server.render_query("example.com"), "-B example.com\r\n");
+    }
+
+    #[test]
+    fn load_server_config_from_empty_when_path_is_none() {
+        assert!(load_server_config_from(None).is_empty());
+    }
+
+    #[test]
+    fn load_server_config_from_empty_when_file_is_missing() {
+        assert!(load_server_config_from(Some("/nonexistent/rwhois-config.json")).is_empty());
+    }
+
+    #[test]
+    fn load_server_config_from_empty_on_malformed_json() {
+        let path = std::env::temp_dir().join(format!(
+            "rwhois-test-malformed-{}.json",
+            std::process::id()
+        ));
+        std::fs::write(&path, "not valid json").unwrap();
+        let result = load_server_config_from(Some(path.to_str().unwrap()));
+        std::fs::remove_file(&path).unwrap();
+        assert!(result.is_empty());
+    }
+
+    #[test]
+    fn load_server_config_from_parses_entries_with_defaults() {
+        let path = std::env::temp_dir().join(format!(
+            "rwhois-test-valid-{}.json",
+            std::process::id()
+        ));
+        std::fs::write(
+            &path,
+            r#"{"ripe": {"host": "whois.ripe.net", "query_template": "-B $addr\r\n"}}"#,
+        )
+        .unwrap();
+        let result = load_server_config_from(Some(path.to_str().unwrap()));
+        std::fs::remove_file(&path).unwrap();
+
+        let entry = result.get("ripe").expect("ripe entry should be present");
+        assert_eq!(entry.host, "whois.ripe.net");
+        assert_eq!(entry.port, 43);
+        assert_eq!(entry.query_template, "-B $addr\r\n");
+    }
+
+    #[test]
+    fn resolve_server_from_prefers_config_over_lut_and_cache() {
+        let mut config = HashMap::new();
+        config.insert("com".to_string(), ServerEntry::from_lut_host("whois.override.example"));
+        let mut lut = HashMap::new();
+        lut.insert("com", "whois.verisign-grs.com");
+        let mut discovered = HashMap::new();
+        discovered.insert("com".to_string(), "whois.discovered.example".to_string());
+
+        let resolved = resolve_server_from("com", &config, &lut, &discovered).unwrap();
+        assert_eq!(resolved.host, "whois.override.example");
+    }
+
+    #[test]
+    fn resolve_server_from_falls_back_to_lut_when_config_misses() {
+        let config = HashMap::new();
+        let mut lut = HashMap::new();
+        lut.insert("com", "whois.verisign-grs.com");
+        let discovered = HashMap::new();
+
+        let resolved = resolve_server_from("com", &config, &lut, &discovered).unwrap();
+        assert_eq!(resolved.host, "whois.verisign-grs.com");
+    }
+
+    #[test]
+    fn resolve_server_from_falls_back_to_discovered_cache() {
+        let config = HashMap::new();
+        let lut = HashMap::new();
+        let mut discovered = HashMap::new();
+        discovered.insert("zz".to_string(), "whois.discovered.example".to_string());
+
+        let resolved = resolve_server_from("zz", &config, &lut, &discovered).unwrap();
+        assert_eq!(resolved.host, "whois.discovered.example");
+    }
+
+    #[test]
+    fn resolve_server_from_none_when_absent_everywhere() {
+        let config = HashMap::new();
+        let lut = HashMap::new();
+        let discovered = HashMap::new();
+
+        assert!(resolve_server_from("zz", &config, &lut, &discovered).is_none());
+    }
+}
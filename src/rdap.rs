@@ -0,0 +1,146 @@
+//! RDAP-over-HTTPS backend: an alternative to port-43 whois for TLDs that
+//! publish a structured JSON registry via RDAP bootstrap.
+
+use std::sync::Arc;
+
+use hashbrown::HashMap;
+use lazy_static::lazy_static;
+use log::error;
+use tokio::net::TcpStream;
+use tokio::prelude::*;
+use tokio::time::timeout;
+use tokio_rustls::rustls::ClientConfig;
+use tokio_rustls::webpki::DNSNameRef;
+use tokio_rustls::TlsConnector;
+
+// Flips the whole server into RDAP mode by default; a per-query
+// `rdap <name>` prefix always forces it regardless of this.
+pub const RDAP_MODE_ENV: &str = "RWHOIS_MODE";
+
+pub const RDAP_QUERY_PREFIX: &str = "rdap";
+const RDAP_MODE_VALUE: &str = RDAP_QUERY_PREFIX;
+
+lazy_static! {
+    // TLD to RDAP base URL, analogous to `WHOIS_LUT` for the whois backend.
+    static ref RDAP_LUT: HashMap<&'static str, &'static str> = {
+        let mut m = HashMap::new();
+        m.insert("com", "https://rdap.verisign.com/com/v1");
+        m.insert("net", "https://rdap.verisign.com/net/v1");
+        m.insert("org", "https://rdap.publicinterestregistry.org/rdap");
+        m.insert("io", "https://rdap.nic.io");
+        m.insert("dev", "https://www.googleapis.com/registry/v2");
+        m
+    };
+}
+
+pub fn rdap_mode_enabled() -> bool {
+    std::env::var(RDAP_MODE_ENV)
+        .map(|v| v.eq_ignore_ascii_case(RDAP_MODE_VALUE))
+        .unwrap_or(false)
+}
+
+#[derive(Debug)]
+#[allow(dead_code)]
+pub enum RdapError {
+    NoBootstrapEntry,
+    InvalidUrl,
+    Io(std::io::Error),
+    Tls(std::io::Error),
+    Timeout(&'static str),
+}
+
+pub async fn query_rdap(domain: &str) -> Result<Vec<u8>, RdapError> {
+    let tld = domain.rsplit('.').next().unwrap_or(domain);
+    let base = RDAP_LUT.get(tld).ok_or(RdapError::NoBootstrapEntry)?;
+
+    let (host, path_prefix) = split_base_url(base).ok_or(RdapError::InvalidUrl)?;
+    let path = format!("{}/domain/{}", path_prefix, domain);
+
+    let body = https_get(host, &path).await?;
+    Ok(body)
+}
+
+fn split_base_url(base: &str) -> Option<(&str, &str)> {
+    let rest = base.strip_prefix("https://")?;
+    match rest.find('/') {
+        Some(idx) => Some((&rest[..idx], &rest[idx..])),
+        None => Some((rest, "")),
+    }
+}
+
+// Bare HTTP/1.1 GET for `path` on `host:443` over TLS, mirroring the
+// tokio-rustls client example.
+async fn https_get(host: &str, path: &str) -> Result<Vec<u8>, RdapError> {
+    let _permit = crate::UPSTREAM_LIMIT.acquire().await;
+
+    let mut config = ClientConfig::new();
+    config
+        .root_store
+        .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    let connector = TlsConnector::from(Arc::new(config));
+
+    let dnsname = DNSNameRef::try_from_ascii_str(host).map_err(|_| RdapError::InvalidUrl)?;
+    let tcp = timeout(*crate::CONNECT_TIMEOUT, TcpStream::connect((host, 443)))
+        .await
+        .map_err(|_| RdapError::Timeout("connect"))?
+        .map_err(RdapError::Io)?;
+    let mut tls = timeout(*crate::READ_TIMEOUT, connector.connect(dnsname, tcp))
+        .await
+        .map_err(|_| RdapError::Timeout("tls handshake"))?
+        .map_err(RdapError::Tls)?;
+
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nAccept: application/rdap+json\r\nConnection: close\r\n\r\n",
+        path, host
+    );
+    timeout(*crate::READ_TIMEOUT, tls.write_all(request.as_bytes()))
+        .await
+        .map_err(|_| RdapError::Timeout("write"))?
+        .map_err(RdapError::Io)?;
+
+    let mut response = Vec::new();
+    timeout(*crate::READ_TIMEOUT, tls.read_to_end(&mut response))
+        .await
+        .map_err(|_| RdapError::Timeout("read"))?
+        .map_err(RdapError::Io)?;
+
+    match split_http_body(&response) {
+        Some(body) => Ok(body.to_vec()),
+        None => {
+            error!("malformed HTTP response from {}", host);
+            Ok(response)
+        }
+    }
+}
+
+fn split_http_body(response: &[u8]) -> Option<&[u8]> {
+    let marker = b"\r\n\r\n";
+    response
+        .windows(marker.len())
+        .position(|w| w == marker)
+        .map(|idx| &response[idx + marker.len()..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{split_base_url, split_http_body};
+
+    #[test]
+    fn split_base_url_with_path() {
+        assert_eq!(
+            split_base_url("https://rdap.verisign.com/com/v1"),
+            Some(("rdap.verisign.com", "/com/v1"))
+        );
+    }
+
+    #[test]
+    fn split_base_url_without_path() {
+        assert_eq!(split_base_url("https://rdap.nic.io"), Some(("rdap.nic.io", "")));
+    }
+
+    #[test]
+    fn split_http_body_after_headers() {
+        let response = b"HTTP/1.1 200 OK\r\nContent-Type: application/rdap+json\r\n\r\n{\"a\":1}";
+        assert_eq!(split_http_body(response), Some(&b"{\"a\":1}"[..]));
+    }
+}